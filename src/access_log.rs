@@ -0,0 +1,151 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+/// Rotate the log file once it grows past this many bytes, keeping a single
+/// `<path>.1` backup.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a UNIX timestamp as a Common Log Format date in UTC,
+/// e.g. `10/Oct/2000:13:55:36 +0000`.
+fn clf_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let (hour, min, sec) = (rem / 3600, (rem % 3600) / 60, rem % 60);
+
+    // Civil date from days since the UNIX epoch (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let year = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = if month <= 2 { year + 1 } else { year };
+
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        min,
+        sec
+    )
+}
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    Combined,
+    Json,
+}
+
+impl LogFormat {
+    pub fn new(name: &str) -> LogFormat {
+        match name {
+            "json" => LogFormat::Json,
+            _ => LogFormat::Combined,
+        }
+    }
+}
+
+/// A single access-log record, captured in `service::get_service`.
+pub struct AccessRecord<'a> {
+    pub host: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub version: &'a str,
+    pub tileset: Option<&'a str>,
+    pub status: u16,
+    pub size: u64,
+    pub elapsed: Duration,
+}
+
+/// Buffered, mutex-guarded writer shared across all request handlers.
+pub struct AccessLogger {
+    path: PathBuf,
+    format: LogFormat,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AccessLogger {
+    pub fn new(path: &Path, format: LogFormat) -> io::Result<AccessLogger> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLogger {
+            path: path.to_path_buf(),
+            format,
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let elapsed_ms = record.elapsed.as_secs_f64() * 1000.0;
+        let line = match self.format {
+            // Apache combined request line followed by the per-tileset
+            // accounting fields (matched tileset and elapsed time) the request
+            // requires, appended as trailing fields so the leading fields stay
+            // standard-parseable.
+            LogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} {} {:.3}ms",
+                record.host,
+                clf_timestamp(ts),
+                record.method,
+                record.path,
+                record.version,
+                record.status,
+                record.size,
+                record.tileset.unwrap_or("-"),
+                elapsed_ms,
+            ),
+            LogFormat::Json => json!({
+                "timestamp": ts,
+                "host": record.host,
+                "method": record.method,
+                "path": record.path,
+                "version": record.version,
+                "tileset": record.tileset,
+                "status": record.status,
+                "size": record.size,
+                "elapsed_ms": elapsed_ms,
+            })
+            .to_string(),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{}", line);
+    }
+
+    /// Flush buffered records to disk, rotating the file if it has grown too
+    /// large. Called on a timer from `main`.
+    pub fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writer.flush();
+        if let Ok(meta) = std::fs::metadata(&self.path) {
+            if meta.len() >= MAX_LOG_SIZE {
+                let backup = PathBuf::from(format!("{}.1", self.path.display()));
+                if std::fs::rename(&self.path, &backup).is_ok() {
+                    if let Ok(file) = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path)
+                    {
+                        *writer = BufWriter::new(file);
+                    }
+                }
+            }
+        }
+    }
+}
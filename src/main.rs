@@ -11,16 +11,39 @@ extern crate serde_json;
 
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
+use serde_json::json;
+use std::collections::HashMap;
 use std::process;
 use std::sync::{RwLock, Arc};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::tiles::TileMeta;
+
+/// Cheap change detector for the live-preview event stream: a tileset is
+/// considered changed when the modification time of its `.mbtiles` file moves.
+fn fingerprint_tilesets(tilesets: &HashMap<String, TileMeta>) -> HashMap<String, u64> {
+    let mut fingerprints = HashMap::new();
+    for (name, meta) in tilesets.iter() {
+        let stamp = std::fs::metadata(&meta.path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        fingerprints.insert(name.clone(), stamp);
+    }
+    fingerprints
+}
 
+mod access_log;
 mod config;
 mod errors;
+mod metrics;
 mod service;
 mod tiles;
 mod utils;
+mod ws;
 
 
 
@@ -45,8 +68,41 @@ async fn main() {
 
     println!("Scan Interval: {}", _si);
 
-    let tilesets = tiles::discover_tilesets(String::new(), args.directory);
-    let shared = Arc::new(RwLock::new(service::SharedData{tileset: tilesets.clone() }));
+    let tilesets = tiles::discover_tilesets(String::new(), args.directory.clone());
+
+    let access_log = match &args.access_log {
+        Some(path) => {
+            let format = access_log::LogFormat::new(&args.log_format);
+            match access_log::AccessLogger::new(path, format) {
+                Ok(logger) => {
+                    println!("Access log: {}", path.display());
+                    Some(Arc::new(logger))
+                }
+                Err(err) => {
+                    println!("Could not open access log {}: {}", path.display(), err);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let (events_tx, _events_rx) = tokio::sync::broadcast::channel::<String>(64);
+
+    let shared = Arc::new(RwLock::new(service::SharedData {
+        tileset: tilesets.clone(),
+        access_log: access_log.clone(),
+        events: events_tx,
+    }));
+
+    if let Some(logger) = access_log {
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                logger.flush();
+            }
+        });
+    }
 
     let addr = ([0, 0, 0, 0], args.port).into();
 
@@ -60,10 +116,23 @@ async fn main() {
         println!("Folder Scan activated, scanning every {}s", _si);
         tokio::task::spawn( async move {
             let _tst = _ts.clone();
+            let events = _tst.read().unwrap().events.clone();
+            let mut fingerprints = fingerprint_tilesets(&_tst.read().unwrap().tileset);
             loop{
                 sleep(Duration::from_secs(u64::from(_si)));
                 println!("Scanning Directory");
-                _tst.write().unwrap().tileset = tiles::discover_tilesets(String::new(),  _d.clone());
+                let new_tilesets = tiles::discover_tilesets(String::new(), _d.clone());
+                let new_fingerprints = fingerprint_tilesets(&new_tilesets);
+                // Notify live previews of any tileset whose backing file changed.
+                for (name, fp) in new_fingerprints.iter() {
+                    if fingerprints.get(name) != Some(fp) {
+                        let _ = events.send(
+                            json!({ "tileset": name, "event": "updated" }).to_string(),
+                        );
+                    }
+                }
+                fingerprints = new_fingerprints;
+                _tst.write().unwrap().tileset = new_tilesets;
             }
 
         });
@@ -73,11 +142,18 @@ async fn main() {
 
 
     let disable_preview = args.disable_preview;
+    let directory = args.directory;
+    let allow_reload = args.allow_reload;
+    let auth_tokens = args.auth_tokens;
+    let public_services = args.public_services;
+    let allow_download = args.allow_download;
     let make_service = make_service_fn(move |_conn| {
         let _s = shared.clone();
         let _subdomain = _subdomain.clone();
         let allowed_hosts = allowed_hosts.clone();
         let headers = headers.clone();
+        let directory = directory.clone();
+        let auth_tokens = auth_tokens.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
 
@@ -87,7 +163,12 @@ async fn main() {
                     headers.clone(),
                     disable_preview,
                     _s.clone(),
-                    _subdomain.clone()
+                    _subdomain.clone(),
+                    directory.clone(),
+                    allow_reload,
+                    auth_tokens.clone(),
+                    public_services,
+                    allow_download,
                 )
             }))
         }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Upper bounds (in seconds) for the tile-latency histogram. The `+Inf` bucket
+/// is emitted implicitly from the total count.
+const LATENCY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Process-wide counters and histograms scraped by the `/metrics` endpoint.
+pub struct Metrics {
+    requests_total: AtomicU64,
+    blank_images_total: AtomicU64,
+    responses: Mutex<HashMap<u16, u64>>,
+    tile_hits: Mutex<HashMap<(String, String), u64>>,
+    latency_buckets: Mutex<[u64; 8]>,
+    latency_sum: Mutex<f64>,
+    latency_count: AtomicU64,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            requests_total: AtomicU64::new(0),
+            blank_images_total: AtomicU64::new(0),
+            responses: Mutex::new(HashMap::new()),
+            tile_hits: Mutex::new(HashMap::new()),
+            latency_buckets: Mutex::new([0; 8]),
+            latency_sum: Mutex::new(0.0),
+            latency_count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc_requests(&self) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_response(&self, status: u16) {
+        *self.responses.lock().unwrap().entry(status).or_insert(0) += 1;
+    }
+
+    pub fn inc_tile_hit(&self, tileset: &str, format: &str) {
+        *self
+            .tile_hits
+            .lock()
+            .unwrap()
+            .entry((tileset.to_string(), format.to_string()))
+            .or_insert(0) += 1;
+    }
+
+    pub fn inc_blank_image(&self) {
+        self.blank_images_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_latency(&self, seconds: f64) {
+        let mut buckets = self.latency_buckets.lock().unwrap();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                buckets[i] += 1;
+            }
+        }
+        *self.latency_sum.lock().unwrap() += seconds;
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the collected metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE mbtileserver_requests_total counter").unwrap();
+        writeln!(
+            out,
+            "mbtileserver_requests_total {}",
+            self.requests_total.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(out, "# TYPE mbtileserver_responses_total counter").unwrap();
+        for (status, count) in self.responses.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "mbtileserver_responses_total{{status=\"{}\"}} {}",
+                status, count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "# TYPE mbtileserver_tile_requests_total counter").unwrap();
+        for ((tileset, format), count) in self.tile_hits.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "mbtileserver_tile_requests_total{{tileset=\"{}\",format=\"{}\"}} {}",
+                tileset, format, count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "# TYPE mbtileserver_blank_images_total counter").unwrap();
+        writeln!(
+            out,
+            "mbtileserver_blank_images_total {}",
+            self.blank_images_total.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        let buckets = self.latency_buckets.lock().unwrap();
+        let count = self.latency_count.load(Ordering::Relaxed);
+        writeln!(out, "# TYPE mbtileserver_tile_latency_seconds histogram").unwrap();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            writeln!(
+                out,
+                "mbtileserver_tile_latency_seconds_bucket{{le=\"{}\"}} {}",
+                bound, buckets[i]
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "mbtileserver_tile_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "mbtileserver_tile_latency_seconds_sum {}",
+            *self.latency_sum.lock().unwrap()
+        )
+        .unwrap();
+        writeln!(out, "mbtileserver_tile_latency_seconds_count {}", count).unwrap();
+
+        out
+    }
+}
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
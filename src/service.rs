@@ -1,13 +1,30 @@
 use std::collections::HashMap;
 
-use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, HOST};
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_DISPOSITION, CONTENT_ENCODING,
+    CONTENT_TYPE, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+};
 use hyper::{Body, Request, Response, StatusCode};
+use tokio::sync::broadcast;
 
 use regex::Regex;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 use serde_json::json;
 
-use crate::tiles::{get_grid_data, get_tile_data, TileMeta, TileSummaryJSON};
+use std::time::Instant;
+
+use hyper::body::HttpBody;
+
+use flate2::read::GzDecoder;
+use std::io::Read;
+use tokio_util::io::ReaderStream;
+
+use crate::access_log::{AccessLogger, AccessRecord};
+use crate::metrics::METRICS;
+use crate::tiles::{
+    discover_tilesets, get_grid_data, get_metadata_rows, get_tile_data, TileMeta, TileSummaryJSON,
+};
 use crate::utils::{encode, get_blank_image, DataFormat};
 
 lazy_static! {
@@ -65,6 +82,31 @@ pub fn tile_map() -> Response<Body> {
     Response::new(body)
 }
 
+fn client_accepts_gzip(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|enc| enc.trim().starts_with("gzip")))
+        .unwrap_or(false)
+}
+
+/// Tiles stored in an mbtiles file may be gzip-compressed (vector tiles) or
+/// stored identity-encoded (raster PNG/JPEG). Sniff the gzip magic bytes rather
+/// than trusting the file format.
+fn is_gzipped(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut decoder = GzDecoder::new(data);
+    let mut out = Vec::new();
+    match decoder.read_to_end(&mut out) {
+        Ok(_) => out,
+        Err(_) => data.to_vec(),
+    }
+}
+
 fn is_host_valid(host: Option<&HeaderValue>, allowed_hosts: &Vec<String>) -> bool {
     if host.is_none() {
         return false;
@@ -90,8 +132,81 @@ fn is_host_valid(host: Option<&HeaderValue>, allowed_hosts: &Vec<String>) -> boo
 
 pub struct SharedData {
     pub tileset: HashMap<String, TileMeta>,
+    pub access_log: Option<Arc<AccessLogger>>,
+    pub events: broadcast::Sender<String>,
+}
+
+/// Derive the tileset name addressed by a request path, for access logging.
+fn matched_tileset(path: &str, tilesets: &HashMap<String, TileMeta>) -> Option<String> {
+    if let Some(matches) = TILE_URL_RE.captures(path) {
+        return Some(matches.name("tile_path").unwrap().as_str().to_string());
+    }
+    if path.starts_with("/services") {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if segments.len() > 1 {
+            let name = segments[1..].join("/");
+            if tilesets.contains_key(&name) {
+                return Some(name);
+            }
+            if segments[segments.len() - 1] == "map" {
+                let name = segments[1..segments.len() - 1].join("/");
+                if tilesets.contains_key(&name) {
+                    return Some(name);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Compare two byte slices in constant time so token matching does not leak
+/// length-prefix information through early returns.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
 }
 
+fn extract_token(request: &Request<Body>) -> Option<String> {
+    if let Some(value) = request.headers().get(AUTHORIZATION) {
+        if let Ok(value) = value.to_str() {
+            if let Some(token) = value.strip_prefix("Bearer ") {
+                return Some(token.to_string());
+            }
+        }
+    }
+    if let Some(query) = request.uri().query() {
+        for pair in query.split('&') {
+            if let Some(token) = pair.strip_prefix("token=") {
+                return Some(token.to_string());
+            }
+        }
+    }
+    None
+}
+
+fn is_token_valid(token: Option<&str>, auth_tokens: &Vec<String>) -> bool {
+    if auth_tokens.is_empty() {
+        return true;
+    }
+    match token {
+        Some(token) => {
+            let mut valid = false;
+            for candidate in auth_tokens.iter() {
+                valid |= constant_time_eq(candidate.as_bytes(), token.as_bytes());
+            }
+            valid
+        }
+        None => false,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn get_service(
     request: Request<Body>,
     allowed_hosts: Vec<String>,
@@ -99,15 +214,135 @@ pub async fn get_service(
     disable_preview: bool,
     shared: Arc<RwLock<SharedData>>,
     subdomain: String,
+    directory: PathBuf,
+    allow_reload: bool,
+    auth_tokens: Vec<String>,
+    public_services: bool,
+    allow_download: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    let logger = shared.read().unwrap().access_log.clone();
+
+    // Capture the request metadata before the body is consumed downstream, so
+    // we can emit a single access-log line once the response is built.
+    let method = request.method().to_string();
+    let version = format!("{:?}", request.version());
+    let path = request
+        .uri()
+        .path()
+        .replace("/api/tileserver/", "/");
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.split(':').next().unwrap().to_string())
+        .unwrap_or_else(|| String::from("-"));
+
+    let start = Instant::now();
+    let response = dispatch(
+        request,
+        allowed_hosts,
+        headers,
+        disable_preview,
+        shared.clone(),
+        subdomain,
+        directory,
+        allow_reload,
+        auth_tokens,
+        public_services,
+        allow_download,
+    )
+    .await?;
+
+    METRICS.inc_response(response.status().as_u16());
+
+    if let Some(logger) = logger {
+        let tileset = {
+            let tilesets = &shared.read().unwrap().tileset;
+            matched_tileset(&path, tilesets)
+        };
+        logger.log(&AccessRecord {
+            host: &host,
+            method: &method,
+            path: &path,
+            version: &version,
+            tileset: tileset.as_deref(),
+            status: response.status().as_u16(),
+            size: response.body().size_hint().exact().unwrap_or(0),
+            elapsed: start.elapsed(),
+        });
+    }
+
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn dispatch(
+    mut request: Request<Body>,
+    allowed_hosts: Vec<String>,
+    headers: Vec<(String, String)>,
+    disable_preview: bool,
+    shared: Arc<RwLock<SharedData>>,
+    subdomain: String,
+    directory: PathBuf,
+    allow_reload: bool,
+    auth_tokens: Vec<String>,
+    public_services: bool,
+    allow_download: bool,
 ) -> Result<Response<Body>, hyper::Error> {
     if !is_host_valid(request.headers().get(HOST), &allowed_hosts) {
         return Ok(forbidden());
     };
 
+    // When tokens are configured, every endpoint is protected except the
+    // `/services` listing, which can be left public via `--public-services`.
+    let authorized = is_token_valid(extract_token(&request).as_deref(), &auth_tokens);
+
+    METRICS.inc_requests();
+
     let uri = request.uri();
 
     let path = uri.path().replace("/api/tileserver/", "/");
 
+    if path == "/metrics" {
+        // Metrics label series by tileset name, so keep them behind the token
+        // gate to avoid leaking the inventory when discovery is closed.
+        if !authorized {
+            return Ok(forbidden());
+        }
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(METRICS.render()))
+            .unwrap());
+    }
+
+    if path == "/reload" {
+        if !authorized {
+            return Ok(forbidden());
+        }
+        if !allow_reload {
+            return Ok(forbidden());
+        }
+        let new_tilesets = discover_tilesets(String::new(), directory);
+        let mut data = shared.write().unwrap();
+        let added: Vec<String> = new_tilesets
+            .keys()
+            .filter(|name| !data.tileset.contains_key(*name))
+            .cloned()
+            .collect();
+        let removed: Vec<String> = data
+            .tileset
+            .keys()
+            .filter(|name| !new_tilesets.contains_key(*name))
+            .cloned()
+            .collect();
+        data.tileset = new_tilesets;
+        let summary = json!({ "added": added, "removed": removed });
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&summary).unwrap()))
+            .unwrap());
+    }
+
     let scheme = match uri.scheme_str() {
         Some(scheme) => format!("{}://", scheme),
         None => String::from("https://"),
@@ -119,9 +354,116 @@ pub async fn get_service(
 
     let tilesets = shared.read().unwrap().tileset.clone();
 
+    // WebSocket event stream: /services/<tileset>/events
+    if path.starts_with("/services") && path.trim_end_matches('/').ends_with("/events") {
+        if !authorized {
+            return Ok(forbidden());
+        }
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let tileset = segments[1..segments.len() - 1].join("/");
+        if !tilesets.contains_key(&tileset) {
+            return Ok(not_found());
+        }
+        let key = match request.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) => match key.to_str() {
+                Ok(key) => key.to_string(),
+                Err(_) => return Ok(bad_request(String::from("Invalid WebSocket key"))),
+            },
+            None => return Ok(bad_request(String::from("Expected WebSocket upgrade"))),
+        };
+        let accept = crate::ws::accept_key(&key);
+        let mut rx = shared.read().unwrap().events.subscribe();
+        tokio::task::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            if let Ok(mut upgraded) = hyper::upgrade::on(&mut request).await {
+                while let Ok(msg) = rx.recv().await {
+                    let matches = serde_json::from_str::<serde_json::Value>(&msg)
+                        .ok()
+                        .and_then(|v| v.get("tileset").and_then(|t| t.as_str()).map(String::from))
+                        .map(|t| t == tileset)
+                        .unwrap_or(false);
+                    if matches && upgraded.write_all(&crate::ws::text_frame(&msg)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        return Ok(Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "Upgrade")
+            .header(SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    // Raw .mbtiles download: /services/<tileset>/download
+    if path.starts_with("/services") && path.trim_end_matches('/').ends_with("/download") {
+        if !authorized {
+            return Ok(forbidden());
+        }
+        // Check the flag before the tileset lookup so a disabled endpoint does
+        // not become an existence oracle (403 vs 404).
+        if !allow_download {
+            return Ok(forbidden());
+        }
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let tileset = segments[1..segments.len() - 1].join("/");
+        let tile_meta = match tilesets.get(&tileset) {
+            Some(tile_meta) => tile_meta,
+            None => return Ok(not_found()),
+        };
+        // Stream the file rather than buffering it: an mbtiles file can be many
+        // gigabytes and one buffered copy per request is a memory-exhaustion risk.
+        let file = match tokio::fs::File::open(&tile_meta.path).await {
+            Ok(file) => file,
+            Err(_) => return Ok(not_found()),
+        };
+        let filename = tile_meta
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| format!("{}.mbtiles", tileset));
+        let stream = ReaderStream::new(file);
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            )
+            .body(Body::wrap_stream(stream))
+            .unwrap());
+    }
+
+    // Raw metadata table dump: /services/<tileset>/metadata.json
+    if path.starts_with("/services") && path.ends_with("/metadata.json") {
+        if !authorized {
+            return Ok(forbidden());
+        }
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let tileset = segments[1..segments.len() - 1].join("/");
+        let tile_meta = match tilesets.get(&tileset) {
+            Some(tile_meta) => tile_meta,
+            None => return Ok(not_found()),
+        };
+        let rows = get_metadata_rows(&tile_meta.connection_pool.get().unwrap());
+        let mut object = serde_json::Map::new();
+        for (key, value) in rows {
+            object.insert(key, json!(value));
+        }
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_string(&serde_json::Value::Object(object)).unwrap(),
+            ))
+            .unwrap());
+    }
 
     match TILE_URL_RE.captures(&path) {
         Some(matches) => {
+            if !authorized {
+                return Ok(forbidden());
+            }
             let tile_path = matches.name("tile_path").unwrap().as_str();
             let tile_meta = tilesets.get(tile_path).unwrap();
             let z = matches.name("z").unwrap().as_str().parse::<u32>().unwrap();
@@ -140,7 +482,10 @@ pub async fn get_service(
                 response = response.header(&k, &v);
             }
 
-            return match data_format {
+            let accepts_gzip = client_accepts_gzip(&request);
+
+            let start = Instant::now();
+            let result = match data_format {
                 "json" => match tile_meta.grid_format {
                     Some(grid_format) => match get_grid_data(
                         &tile_meta.connection_pool.get().unwrap(),
@@ -151,29 +496,56 @@ pub async fn get_service(
                     ) {
                         Ok(data) => {
                             let data = serde_json::to_vec(&data).unwrap();
-                            Ok(response
-                                .header(CONTENT_TYPE, DataFormat::JSON.content_type())
-                                .header(CONTENT_ENCODING, "gzip")
-                                .body(Body::from(encode(&data)))
-                                .unwrap())
+                            METRICS.inc_tile_hit(tile_path, "json");
+                            let response =
+                                response.header(CONTENT_TYPE, DataFormat::JSON.content_type());
+                            if accepts_gzip {
+                                Ok(response
+                                    .header(CONTENT_ENCODING, "gzip")
+                                    .body(Body::from(encode(&data)))
+                                    .unwrap())
+                            } else {
+                                Ok(response.body(Body::from(data)).unwrap())
+                            }
                         }
                         Err(_) => Ok(no_content()),
                     },
                     None => Ok(not_found()),
                 },
                 "pbf" => match get_tile_data(&tile_meta.connection_pool.get().unwrap(), z, x, y) {
-                    Ok(data) => Ok(response
-                        .header(CONTENT_TYPE, DataFormat::PBF.content_type())
-                        .header(CONTENT_ENCODING, "gzip")
-                        .body(Body::from(data))
-                        .unwrap()),
+                    Ok(data) => {
+                        METRICS.inc_tile_hit(tile_path, "pbf");
+                        let response =
+                            response.header(CONTENT_TYPE, DataFormat::PBF.content_type());
+                        if is_gzipped(&data) {
+                            if accepts_gzip {
+                                // Stored gzip, client accepts it: pass through.
+                                Ok(response
+                                    .header(CONTENT_ENCODING, "gzip")
+                                    .body(Body::from(data))
+                                    .unwrap())
+                            } else {
+                                // Stored gzip, client does not accept it: inflate.
+                                Ok(response.body(Body::from(gunzip(&data))).unwrap())
+                            }
+                        } else {
+                            // Stored identity: never claim gzip.
+                            Ok(response.body(Body::from(data)).unwrap())
+                        }
+                    }
                     Err(_) => Ok(no_content()),
                 },
                 _ => {
                     let data =
                         match get_tile_data(&tile_meta.connection_pool.get().unwrap(), z, x, y) {
-                            Ok(data) => data,
-                            Err(_) => get_blank_image(),
+                            Ok(data) => {
+                                METRICS.inc_tile_hit(tile_path, data_format);
+                                data
+                            }
+                            Err(_) => {
+                                METRICS.inc_blank_image();
+                                get_blank_image()
+                            }
                         };
                     Ok(response
                         .header(CONTENT_TYPE, DataFormat::new(data_format).content_type())
@@ -181,6 +553,8 @@ pub async fn get_service(
                         .unwrap())
                 }
             };
+            METRICS.observe_latency(start.elapsed().as_secs_f64());
+            return result;
         }
         None => {
             if path.starts_with("/services") {
@@ -193,6 +567,9 @@ pub async fn get_service(
                 let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
                 if segments.len() == 1 {
                     // Root url (/services): show all services
+                    if !authorized && !public_services {
+                        return Ok(forbidden());
+                    }
                     let mut tiles_summary = Vec::new();
                     for (tile_name, tile_meta) in tilesets {
                         tiles_summary.push(TileSummaryJSON {
@@ -207,7 +584,11 @@ pub async fn get_service(
                         .unwrap()); // TODO handle error
                 }
 
-                // Tileset details (/services/<tileset-path>)
+                // Tileset details (/services/<tileset-path>) and everything below
+                // it are protected whenever tokens are configured.
+                if !authorized {
+                    return Ok(forbidden());
+                }
                 let tile_name = segments[1..].join("/");
                 let tile_meta = match tilesets.get(&tile_name) {
                     Some(tile_meta) => tile_meta.clone(),
@@ -311,22 +692,60 @@ mod tests {
         headers: Option<Vec<(String, String)>>,
         disable_preview: bool,
     ) -> Response<Body> {
-        let request = Request::get(path)
+        setup_with_auth(
+            host,
+            path,
+            allowed_hosts,
+            headers,
+            disable_preview,
+            vec![],
+            false,
+            None,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn setup_with_auth(
+        host: &str,
+        path: &str,
+        allowed_hosts: Option<Vec<String>>,
+        headers: Option<Vec<(String, String)>>,
+        disable_preview: bool,
+        auth_tokens: Vec<String>,
+        public_services: bool,
+        bearer: Option<&str>,
+    ) -> Response<Body> {
+        let mut builder = Request::get(path)
             .header("host", host)
-            .body(Body::from(""))
-            .unwrap();
-        let subdomain = "";
+            .header("accept-encoding", "gzip");
+        if let Some(token) = bearer {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        let request = builder.body(Body::from("")).unwrap();
+        let subdomain = String::new();
         let tilesets = discover_tilesets(String::new(), PathBuf::from("./tiles"));
+        let (events, _rx) = broadcast::channel(16);
+        let shared = Arc::new(RwLock::new(SharedData {
+            tileset: tilesets,
+            access_log: None,
+            events,
+        }));
         get_service(
             request,
-            tilesets,
             allowed_hosts.unwrap_or(vec![String::from("*")]),
             headers.unwrap_or(vec![]),
             disable_preview,
-            subdomain.clone(),
+            shared,
+            subdomain,
+            PathBuf::from("./tiles"),
+            false,
+            auth_tokens,
+            public_services,
+            false,
         )
-            .await
-            .unwrap()
+        .await
+        .unwrap()
     }
 
     #[tokio::test]
@@ -507,4 +926,114 @@ mod tests {
             .await;
         assert_eq!(response.status(), 404);
     }
+
+    #[tokio::test]
+    async fn auth_missing_token_forbidden() {
+        let response = setup_with_auth(
+            "localhost",
+            "/services/geography-class-png",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            false,
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn auth_valid_token_header() {
+        let response = setup_with_auth(
+            "localhost",
+            "/services/geography-class-png",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            false,
+            Some("s3cret"),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn auth_invalid_token_header() {
+        let response = setup_with_auth(
+            "localhost",
+            "/services/geography-class-png",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            false,
+            Some("wrong"),
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn auth_valid_token_query() {
+        let response = setup_with_auth(
+            "localhost",
+            "/services/geography-class-png?token=s3cret",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            false,
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn auth_public_services_listing_open() {
+        // The /services listing stays reachable without a token when opted in,
+        let listing = setup_with_auth(
+            "localhost",
+            "/services",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            true,
+            None,
+        )
+        .await;
+        assert_eq!(listing.status(), 200);
+        // but individual tilesets remain protected.
+        let details = setup_with_auth(
+            "localhost",
+            "/services/geography-class-png",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            true,
+            None,
+        )
+        .await;
+        assert_eq!(details.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn auth_services_listing_protected_by_default() {
+        let response = setup_with_auth(
+            "localhost",
+            "/services",
+            None,
+            None,
+            false,
+            vec![String::from("s3cret")],
+            false,
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+    }
 }